@@ -16,6 +16,18 @@ fn default_constant_fold() -> bool {
     true
 }
 
+/// Which `CostFunction` to extract expressions with.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ExtractMode {
+    /// Weighted AST size (see `cost_weights`).
+    #[default]
+    Default,
+    /// Prefer factored polynomial forms, e.g. Horner-style products of
+    /// monic factors, which are usually more accurate to evaluate.
+    Factor,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 #[serde(tag = "request")]
@@ -28,6 +40,21 @@ enum Request {
         exprs: Vec<String>,
         #[serde(default = "default_constant_fold")]
         constant_fold: bool,
+        /// Per-operator cost weights used for extraction, e.g. `{"exp": 30,
+        /// "/": 5}`. Operators not listed default to a weight of 1. Only
+        /// used when `extract` is `default`.
+        #[serde(default)]
+        cost_weights: std::collections::HashMap<String, usize>,
+        #[serde(default)]
+        extract: ExtractMode,
+        /// When set, each `Comparison` includes the ordered rewrite steps
+        /// connecting `initial_expr` to `final_expr`.
+        #[serde(default)]
+        explanations: bool,
+    },
+    SolveFor {
+        equation: String,
+        variable: String,
     },
 }
 
@@ -48,6 +75,9 @@ enum Response {
         iterations: Vec<math::Iteration>,
         best: Vec<Comparison>,
     },
+    SolveFor {
+        solutions: Vec<math::RecExpr>,
+    },
 }
 
 #[derive(Serialize)]
@@ -56,6 +86,7 @@ struct Comparison {
     initial_cost: usize,
     final_expr: math::RecExpr,
     final_cost: usize,
+    explanation: Option<Vec<math::ExplanationStep>>,
 }
 
 macro_rules! respond_error {
@@ -96,6 +127,9 @@ impl State {
             Request::SimplifyExpressions {
                 exprs,
                 constant_fold,
+                cost_weights,
+                extract,
+                explanations,
             } => {
                 if self.rewrites.is_empty() {
                     return Response::Error {
@@ -103,41 +137,151 @@ impl State {
                     };
                 }
 
-                let analysis = math::ConstantFold { constant_fold };
+                let analysis = math::ConstantFold {
+                    constant_fold,
+                    explanations,
+                    ..math::ConstantFold::default()
+                };
                 let mut runner = math::Runner::new(analysis).with_node_limit(10_000);
+                if explanations {
+                    runner = runner.with_explanations_enabled();
+                }
                 for expr in exprs {
                     let e = respond_error!(expr.parse());
                     runner = runner.with_expr(&e);
                 }
 
-                let initial: Vec<(usize, math::RecExpr)> = {
-                    let mut extractor = egg::Extractor::new(&runner.egraph, egg::AstSize);
-                    let find_best = |&id| extractor.find_best(id);
-                    runner.roots.iter().map(find_best).collect()
+                assert!(self.rewrites.len() > 0);
+
+                let extracted: Vec<(usize, math::RecExpr, usize, math::RecExpr)> = match extract {
+                    ExtractMode::Default => {
+                        let initial: Vec<(usize, math::RecExpr)> = {
+                            let cost_fn = math::WeightedAstSize {
+                                weights: cost_weights.clone(),
+                            };
+                            let mut extractor = egg::Extractor::new(&runner.egraph, cost_fn);
+                            let find_best = |&id| extractor.find_best(id);
+                            runner.roots.iter().map(find_best).collect()
+                        };
+
+                        runner = runner.run(&self.rewrites);
+
+                        let cost_fn = math::WeightedAstSize {
+                            weights: cost_weights,
+                        };
+                        let mut extractor = egg::Extractor::new(&runner.egraph, cost_fn);
+                        runner
+                            .roots
+                            .iter()
+                            .zip(initial)
+                            .map(|(id, (initial_cost, initial_expr))| {
+                                let (final_cost, final_expr) = extractor.find_best(*id);
+                                (initial_cost, initial_expr, final_cost, final_expr)
+                            })
+                            .collect()
+                    }
+                    ExtractMode::Factor => {
+                        let initial: Vec<(usize, math::RecExpr)> = {
+                            let mut extractor =
+                                egg::Extractor::new(&runner.egraph, math::FactorNormalForm);
+                            let find_best = |&id| {
+                                let (cost, expr) = extractor.find_best(id);
+                                (cost.score(), expr)
+                            };
+                            runner.roots.iter().map(find_best).collect()
+                        };
+
+                        runner = runner.run(&self.rewrites);
+
+                        let mut extractor =
+                            egg::Extractor::new(&runner.egraph, math::FactorNormalForm);
+                        runner
+                            .roots
+                            .iter()
+                            .zip(initial)
+                            .map(|(id, (initial_cost, initial_expr))| {
+                                let (final_cost, final_expr) = extractor.find_best(*id);
+                                (initial_cost, initial_expr, final_cost.score(), final_expr)
+                            })
+                            .collect()
+                    }
                 };
 
-                assert!(self.rewrites.len() > 0);
-                runner = runner.run(&self.rewrites);
+                let best = extracted
+                    .into_iter()
+                    .map(|(initial_cost, initial_expr, final_cost, final_expr)| {
+                        let explanation = if explanations {
+                            Some(math::explain_rewrite_path(
+                                &mut runner.egraph,
+                                &initial_expr,
+                                &final_expr,
+                            ))
+                        } else {
+                            None
+                        };
+                        Comparison {
+                            initial_cost,
+                            initial_expr,
+                            final_cost,
+                            final_expr,
+                            explanation,
+                        }
+                    })
+                    .collect();
 
-                let mut extractor = egg::Extractor::new(&runner.egraph, egg::AstSize);
                 Response::SimplifyExpressions {
                     iterations: runner.iterations,
-                    best: runner
-                        .roots
-                        .iter()
-                        .zip(initial)
-                        .map(|(id, (initial_cost, initial_expr))| {
-                            let (final_cost, final_expr) = extractor.find_best(*id);
-                            Comparison {
-                                initial_cost,
-                                initial_expr,
-                                final_cost,
-                                final_expr,
-                            }
-                        })
-                        .collect(),
+                    best,
                 }
             }
+            Request::SolveFor { equation, variable } => {
+                if self.rewrites.is_empty() {
+                    return Response::Error {
+                        error: "You haven't loaded any rewrites yet!".into(),
+                    };
+                }
+
+                let parsed = respond_error!(equation.parse());
+                let equation = math::as_equation(parsed);
+
+                // `variable` is spliced into a pattern string below, so make sure it
+                // actually parses as a bare `Variable` first -- otherwise a name that
+                // collides with a language token (`E`, `PI`, `NAN`, ...) would silently
+                // build a pattern over that token's constant instead.
+                let parsed_variable: math::RecExpr = respond_error!(variable.parse());
+                if !matches!(parsed_variable.as_ref(), [math::Math::Variable(_)]) {
+                    return Response::Error {
+                        error: format!("`{}` is not a valid variable name", variable),
+                    };
+                }
+
+                let analysis = math::ConstantFold::default();
+                let mut runner = math::Runner::new(analysis)
+                    .with_node_limit(10_000)
+                    .with_expr(&equation);
+
+                let solve_rewrites = math::solve_rewrites();
+                let rewrites: Vec<&math::Rewrite> =
+                    self.rewrites.iter().chain(solve_rewrites.iter()).collect();
+                runner = runner.run(rewrites);
+
+                let pattern: egg::Pattern<math::Math> =
+                    respond_error!(format!("(= {} ?rhs)", variable).parse());
+                let rhs_var: egg::Var = "?rhs".parse().unwrap();
+
+                let mut extractor = egg::Extractor::new(&runner.egraph, egg::AstSize);
+                let mut solutions: Vec<math::RecExpr> = vec![];
+                for m in pattern.search(&runner.egraph) {
+                    for subst in &m.substs {
+                        let (_, expr) = extractor.find_best(subst[rhs_var]);
+                        if !solutions.contains(&expr) {
+                            solutions.push(expr);
+                        }
+                    }
+                }
+
+                Response::SolveFor { solutions }
+            }
         }
     }
 }
@@ -163,3 +307,41 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explanations_survive_constant_folding() {
+        let mut state = State::default();
+        state.handle_request(Request::LoadRewrites {
+            rewrites: vec![RewriteStr {
+                name: "add-0".into(),
+                lhs: "(+ ?a 0)".into(),
+                rhs: "?a".into(),
+            }],
+        });
+
+        let response = state.handle_request(Request::SimplifyExpressions {
+            exprs: vec!["(+ (+ 1 1) 0)".into()],
+            constant_fold: true,
+            cost_weights: Default::default(),
+            extract: ExtractMode::Default,
+            explanations: true,
+        });
+
+        let best = match response {
+            Response::SimplifyExpressions { best, .. } => best,
+            Response::Error { error } => panic!("unexpected error: {}", error),
+            _ => panic!("expected a SimplifyExpressions response"),
+        };
+
+        assert_eq!(best.len(), 1);
+        let steps = best[0]
+            .explanation
+            .as_ref()
+            .expect("explanations were requested");
+        assert!(!steps.is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use egg::*;
 
 use num_bigint::BigInt;
 use num_rational::Ratio;
-use num_traits::{Pow, Signed, Zero};
+use num_traits::{Pow, Signed, ToPrimitive, Zero};
 
 pub type Constant = num_rational::BigRational;
 pub type RecExpr = egg::RecExpr<Math>;
@@ -41,6 +41,7 @@ define_language! {
         "or" = Or([Id; 2]),
 
         // comparison
+        "=" = Eq([Id; 2]),
         "<" = Less([Id; 2]),
         ">" = Greater([Id; 2]),
         "<=" = LessEq([Id; 2]),
@@ -118,9 +119,303 @@ define_language! {
     }
 }
 
+/// A `CostFunction` that sums child costs plus a per-operator weight, so that
+/// e.g. `sin`/`exp`/`pow`/`/` can be penalized relative to cheaper ops like `+`.
+/// Operators not present in `weights` default to a weight of 1, matching
+/// `egg::AstSize`.
+pub struct WeightedAstSize {
+    pub weights: std::collections::HashMap<String, usize>,
+}
+
+impl CostFunction<Math> for WeightedAstSize {
+    type Cost = usize;
+    fn cost<C>(&mut self, enode: &Math, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        let weight = self.weights.get(&enode.to_string()).copied().unwrap_or(1);
+        enode.fold(weight, |sum, id| sum + costs(id))
+    }
+}
+
+/// Statistics tracked bottom-up by `FactorNormalForm` for an expression that
+/// looks like a polynomial.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PolyStat {
+    /// Total polynomial degree.
+    pub degree: usize,
+    /// Number of non-constant factors multiplied together at the top level.
+    pub factors: usize,
+    /// Number of `+`/`-`/`*`/`neg`/`pow` operations used to build this node.
+    pub ops: usize,
+    /// A single term like `3 * x^2 * y`.
+    pub monomial: bool,
+    /// A sum of monomials, e.g. `x^2 + 2*x + 1`.
+    pub sum_of_monomials: bool,
+    /// Leading coefficient is 1, e.g. `x + 1` but not `2*x + 1`. A sum or
+    /// difference counts as monic when either operand is, without comparing
+    /// the operands' degrees or signs, so `x + 2*x^2` also counts as monic;
+    /// `neg` keeps its operand's flag.
+    pub monic: bool,
+    /// A product of monic polynomials times at least one constant,
+    /// e.g. `2 * (x + 1) * (y - 3)`.
+    pub factorized: bool,
+    /// The node's value if it is literally a bare constant, so callers (e.g.
+    /// `pow`'s exponent check) can see the actual number rather than just
+    /// its degree.
+    pub constant: Option<Constant>,
+}
+
+impl PolyStat {
+    fn constant(value: Constant) -> Self {
+        PolyStat {
+            degree: 0,
+            factors: 0,
+            ops: 0,
+            monomial: true,
+            sum_of_monomials: true,
+            monic: false,
+            factorized: false,
+            constant: Some(value),
+        }
+    }
+
+    fn variable() -> Self {
+        PolyStat {
+            degree: 1,
+            factors: 1,
+            ops: 0,
+            monomial: true,
+            sum_of_monomials: true,
+            monic: true,
+            factorized: true,
+            constant: None,
+        }
+    }
+
+    /// The number of "K minus factors" used to rank factorized forms: more
+    /// factors score better, so this saturates at 0 rather than going negative.
+    const K: usize = 9;
+
+    fn score(&self) -> usize {
+        if self.factorized {
+            100 * (Self::K.saturating_sub(self.factors)) + self.ops
+        } else {
+            1000 + self.ops
+        }
+    }
+}
+
+/// The `Cost` produced by `FactorNormalForm`: either the node uses an
+/// operator that has no place in a polynomial at all, or it tracks how
+/// polynomial-like (and how factored) the expression is.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FactorCost {
+    UnwantedOps,
+    Polynomial(PolyStat),
+}
+
+impl FactorCost {
+    /// A single scalar used to rank `FactorCost`s: lower is better, with
+    /// `UnwantedOps` always worse than any polynomial, non-factorized
+    /// polynomials worse than factorized ones, and among factorized forms
+    /// more factors and fewer ops winning.
+    pub fn score(&self) -> usize {
+        match self {
+            FactorCost::UnwantedOps => 10_000,
+            FactorCost::Polynomial(stat) => stat.score(),
+        }
+    }
+}
+
+impl PartialOrd for FactorCost {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FactorCost {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.score().cmp(&other.score())
+    }
+}
+
+/// Extracts the most-factored polynomial normal form of an expression,
+/// e.g. preferring `(x + 1) * (x - 2)` over `x * x - x - 2`, since factored
+/// forms are usually more accurate to evaluate in floating point.
+pub struct FactorNormalForm;
+
+impl CostFunction<Math> for FactorNormalForm {
+    type Cost = FactorCost;
+
+    fn cost<C>(&mut self, enode: &Math, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        fn poly<C: FnMut(Id) -> FactorCost>(costs: &mut C, id: Id) -> Option<PolyStat> {
+            match costs(id) {
+                FactorCost::Polynomial(stat) => Some(stat),
+                FactorCost::UnwantedOps => None,
+            }
+        }
+
+        let stat = match enode {
+            Math::Constant(c) => Some(PolyStat::constant(c.clone())),
+            Math::Variable(_) => Some(PolyStat::variable()),
+            Math::Neg(a) => poly(&mut costs, *a).map(|a| PolyStat {
+                ops: a.ops + 1,
+                constant: None,
+                ..a
+            }),
+            Math::Add([a, b]) | Math::Sub([a, b]) => poly(&mut costs, *a)
+                .zip(poly(&mut costs, *b))
+                .map(|(a, b)| PolyStat {
+                    degree: a.degree.max(b.degree),
+                    factors: if a.degree.max(b.degree) > 0 { 1 } else { 0 },
+                    ops: a.ops + b.ops + 1,
+                    monomial: false,
+                    sum_of_monomials: (a.monomial || a.sum_of_monomials)
+                        && (b.monomial || b.sum_of_monomials),
+                    monic: a.monic || b.monic,
+                    factorized: false,
+                    constant: None,
+                }),
+            Math::Mul([a, b]) => poly(&mut costs, *a)
+                .zip(poly(&mut costs, *b))
+                .map(|(a, b)| {
+                    let (factors, factorized) = if a.monic && b.monic {
+                        (a.factors + b.factors, true)
+                    } else if a.factors == 0 && a.ops == 0 && (b.monic || b.factorized) {
+                        (b.factors, true)
+                    } else if b.factors == 0 && b.ops == 0 && (a.monic || a.factorized) {
+                        (a.factors, true)
+                    } else {
+                        (0, false)
+                    };
+                    PolyStat {
+                        degree: a.degree + b.degree,
+                        factors,
+                        ops: a.ops + b.ops + 1,
+                        monomial: a.monomial && b.monomial,
+                        sum_of_monomials: a.monomial && b.monomial,
+                        monic: a.monic && b.monic,
+                        factorized,
+                        constant: None,
+                    }
+                }),
+            Math::Pow([a, b]) => poly(&mut costs, *a).and_then(|a| {
+                // Only a non-negative integer exponent keeps this a polynomial;
+                // a fractional or negative exponent is a root or reciprocal.
+                let exponent = poly(&mut costs, *b)
+                    .and_then(|b| b.constant)
+                    .filter(|c| c.is_integer() && !c.is_negative())
+                    .and_then(|c| c.numer().to_usize());
+                exponent.map(|n| PolyStat {
+                    degree: a.degree * n,
+                    ops: a.ops + 1,
+                    constant: None,
+                    ..a
+                })
+            }),
+            _ => None,
+        };
+
+        match stat {
+            Some(stat) => FactorCost::Polynomial(stat),
+            None => FactorCost::UnwantedOps,
+        }
+    }
+}
+
+/// One step of a flattened explanation: the rule that produced `expr` from
+/// the previous step (`None` for the very first step).
+#[derive(Clone, serde::Serialize)]
+pub struct ExplanationStep {
+    pub rule: Option<String>,
+    pub expr: RecExpr,
+}
+
+/// Explains how `lhs` was rewritten into `rhs` as an ordered list of rule
+/// applications, each carrying the rule applied and the whole expression
+/// after that step; the sub-term the rule matched is not reported
+/// separately. Requires `egraph`'s `Runner` to have been built with
+/// explanations enabled.
+pub fn explain_rewrite_path(
+    egraph: &mut EGraph,
+    lhs: &RecExpr,
+    rhs: &RecExpr,
+) -> Vec<ExplanationStep> {
+    // A rewrite below the root is recorded on the sub-term it touched, so
+    // search the whole flat term for the rule, not just its root.
+    fn rule_of(term: &FlatTerm<Math>) -> Option<Symbol> {
+        term.forward_rule
+            .or(term.backward_rule)
+            .or_else(|| term.children.iter().find_map(rule_of))
+    }
+
+    let mut explanation = egraph.explain_equivalence(lhs, rhs);
+    explanation
+        .make_flat_explanation()
+        .iter()
+        .map(|term| ExplanationStep {
+            rule: rule_of(term).map(|rule| rule.to_string()),
+            expr: term.get_recexpr(),
+        })
+        .collect()
+}
+
+/// Turns a bare expression into an equation by asserting it equals zero,
+/// unless it already has `=` at its root.
+pub fn as_equation(expr: RecExpr) -> RecExpr {
+    if let Some(Math::Eq(_)) = expr.as_ref().last() {
+        return expr;
+    }
+    let mut nodes: Vec<Math> = expr.as_ref().to_vec();
+    let lhs = Id::from(nodes.len() - 1);
+    nodes.push(Math::Constant(Ratio::from_integer(BigInt::from(0))));
+    let rhs = Id::from(nodes.len() - 1);
+    nodes.push(Math::Eq([lhs, rhs]));
+    nodes.into()
+}
+
+/// Built-in algebraic rewrites for moving terms across an equation's `=`,
+/// used by `SolveFor` to isolate a variable. These run alongside whatever
+/// rewrites were loaded via `LoadRewrites`.
+pub fn solve_rewrites() -> Vec<Rewrite> {
+    let rules: &[(&str, &str, &str)] = &[
+        ("solve-swap", "(= ?a ?b)", "(= ?b ?a)"),
+        ("solve-add-lhs", "(= (+ ?a ?b) ?c)", "(= ?a (- ?c ?b))"),
+        ("solve-add-rhs", "(= (+ ?a ?b) ?c)", "(= ?b (- ?c ?a))"),
+        ("solve-sub-lhs", "(= (- ?a ?b) ?c)", "(= ?a (+ ?c ?b))"),
+        ("solve-sub-rhs", "(= (- ?a ?b) ?c)", "(= ?b (- ?a ?c))"),
+        ("solve-mul-lhs", "(= (* ?a ?b) ?c)", "(= ?a (/ ?c ?b))"),
+        ("solve-mul-rhs", "(= (* ?a ?b) ?c)", "(= ?b (/ ?c ?a))"),
+        ("solve-div-lhs", "(= (/ ?a ?b) ?c)", "(= ?a (* ?c ?b))"),
+        ("solve-div-rhs", "(= (/ ?a ?b) ?c)", "(= ?b (/ ?a ?c))"),
+        ("solve-neg", "(= (neg ?a) ?b)", "(= ?a (neg ?b))"),
+        ("solve-reciprocal", "(= (/ 1 ?a) ?b)", "(= ?a (/ 1 ?b))"),
+    ];
+    rules
+        .iter()
+        .map(|(name, lhs, rhs)| {
+            Rewrite::new(
+                name.to_string(),
+                name.to_string(),
+                lhs.parse().unwrap(),
+                rhs.parse().unwrap(),
+            )
+        })
+        .collect()
+}
+
 pub struct ConstantFold {
     pub constant_fold: bool,
     pub prune: bool,
+    /// When true, `modify` skips its union/prune: egg panics on a bare
+    /// `union` while explanations are enabled, and pruning non-leaf enodes
+    /// out of an eclass destroys the congruence edges `explain_equivalence`
+    /// needs to reconstruct a proof.
+    pub explanations: bool,
 }
 
 impl Default for ConstantFold {
@@ -128,6 +423,7 @@ impl Default for ConstantFold {
         Self {
             constant_fold: true,
             prune: true,
+            explanations: false,
         }
     }
 }
@@ -196,6 +492,9 @@ impl Analysis<Math> for ConstantFold {
     }
 
     fn modify(egraph: &mut EGraph, id: Id) {
+        if egraph.analysis.explanations {
+            return;
+        }
         if let Some(constant) = egraph[id].data.clone() {
             let added = egraph.add(Math::Constant(constant));
             let (id, _) = egraph.union(id, added);